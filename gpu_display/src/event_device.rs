@@ -0,0 +1,129 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Translates input received by a display surface into Linux `input_event` records and forwards
+//! them to a virtio-input-style sink in the guest.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::slice;
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+
+const SYN_REPORT: u16 = 0;
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const ABS_MT_SLOT: u16 = 0x2f;
+const ABS_MT_POSITION_X: u16 = 0x35;
+const ABS_MT_POSITION_Y: u16 = 0x36;
+const ABS_MT_TRACKING_ID: u16 = 0x39;
+
+/// The kind of physical device an `EventDevice` pretends to be, which determines how the guest
+/// driver interprets the `input_event` stream.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EventDeviceKind {
+    Keyboard,
+    Mouse,
+    Touchscreen,
+}
+
+#[repr(C, packed)]
+struct input_event {
+    tv_sec: i64,
+    tv_usec: i64,
+    type_: u16,
+    code: u16,
+    value: i32,
+}
+
+/// A sink for translated guest input events, registered against a display surface.
+///
+/// `EventDevice` owns the pipe or socket that carries `input_event` records to whatever consumes
+/// them in the guest (typically a virtio-input queue).
+pub struct EventDevice {
+    kind: EventDeviceKind,
+    socket: File,
+}
+
+impl EventDevice {
+    /// Creates an event device of the given `kind` that writes translated events to `socket`.
+    pub fn new(kind: EventDeviceKind, socket: File) -> EventDevice {
+        EventDevice { kind, socket }
+    }
+
+    /// Returns the kind of device this event sink represents.
+    pub fn kind(&self) -> EventDeviceKind {
+        self.kind
+    }
+
+    fn send_event(&mut self, type_: u16, code: u16, value: i32) -> io::Result<()> {
+        let evt = input_event {
+            tv_sec: 0,
+            tv_usec: 0,
+            type_,
+            code,
+            value,
+        };
+        // Safe because `input_event` is a packed, repr(C) struct of plain integers and the slice
+        // length matches its size exactly.
+        let bytes =
+            unsafe { slice::from_raw_parts(&evt as *const input_event as *const u8, size_of::<input_event>()) };
+        self.socket.write_all(bytes)
+    }
+
+    fn send_syn_report(&mut self) -> io::Result<()> {
+        self.send_event(EV_SYN, SYN_REPORT, 0)
+    }
+
+    /// Sends a key press or release, identified by its Linux key code, followed by a sync report.
+    pub fn send_key_event(&mut self, linux_keycode: u16, pressed: bool) -> io::Result<()> {
+        self.send_event(EV_KEY, linux_keycode, pressed as i32)?;
+        self.send_syn_report()
+    }
+
+    /// Sends a relative pointer motion, followed by a sync report.
+    pub fn send_motion_event(&mut self, dx: i32, dy: i32) -> io::Result<()> {
+        self.send_event(EV_REL, REL_X, dx)?;
+        self.send_event(EV_REL, REL_Y, dy)?;
+        self.send_syn_report()
+    }
+
+    /// Sends a pointer button press or release, identified by its Linux button code, followed by
+    /// a sync report.
+    pub fn send_button_event(&mut self, linux_button_code: u16, pressed: bool) -> io::Result<()> {
+        self.send_event(EV_KEY, linux_button_code, pressed as i32)?;
+        self.send_syn_report()
+    }
+
+    /// Sends a multi-touch contact update for the given `slot`, followed by a sync report.
+    ///
+    /// `tracking_id` should be a non-negative, per-contact identifier while the contact is down,
+    /// and `-1` to report the contact being lifted.
+    pub fn send_touch_event(
+        &mut self,
+        slot: i32,
+        tracking_id: i32,
+        x: i32,
+        y: i32,
+    ) -> io::Result<()> {
+        self.send_event(EV_ABS, ABS_MT_SLOT, slot)?;
+        self.send_event(EV_ABS, ABS_MT_TRACKING_ID, tracking_id)?;
+        if tracking_id >= 0 {
+            self.send_event(EV_ABS, ABS_MT_POSITION_X, x)?;
+            self.send_event(EV_ABS, ABS_MT_POSITION_Y, y)?;
+        }
+        self.send_syn_report()
+    }
+}
+
+impl AsRawFd for EventDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
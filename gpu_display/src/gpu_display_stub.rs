@@ -0,0 +1,201 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Headless backend for `DisplayT` that keeps its framebuffers in plain heap memory instead of
+//! talking to a compositor, so the virtio-gpu device can run in environments with no display
+//! server: automated tests, CI, and headless hosts.
+
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use data_model::VolatileSlice;
+use sys_util::EventFd;
+
+use crate::{DisplayT, EventDevice, GpuDisplayError, GpuDisplayFramebuffer};
+
+const BUFFER_COUNT: usize = 2;
+const BYTES_PER_PIXEL: u32 = 4;
+
+struct StubSurface {
+    width: u32,
+    buffers: [Vec<u8>; BUFFER_COUNT],
+    buffer_index: usize,
+}
+
+/// A stub display backend that exercises the whole surface lifecycle without a compositor.
+pub struct DisplayStub {
+    event_fd: EventFd,
+    dmabufs: HashMap<u32, (u32, u32)>,
+    dmabuf_next_id: u32,
+    surfaces: HashMap<u32, StubSurface>,
+    surface_next_id: u32,
+    event_devices: HashMap<u32, (u32, EventDevice)>,
+    event_device_next_id: u32,
+}
+
+impl DisplayStub {
+    /// Creates a new stub display backed by no real compositor.
+    pub fn new() -> Result<DisplayStub, GpuDisplayError> {
+        let event_fd = EventFd::new().map_err(|_| GpuDisplayError::Allocate)?;
+        Ok(DisplayStub {
+            event_fd,
+            dmabufs: Default::default(),
+            dmabuf_next_id: 0,
+            surfaces: Default::default(),
+            surface_next_id: 0,
+            event_devices: Default::default(),
+            event_device_next_id: 0,
+        })
+    }
+
+    fn get_surface(&self, surface_id: u32) -> Option<&StubSurface> {
+        self.surfaces.get(&surface_id)
+    }
+}
+
+impl DisplayT for DisplayStub {
+    fn create_surface(
+        &mut self,
+        _parent_surface_id: Option<u32>,
+        width: u32,
+        height: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        let fb_size = (width * BYTES_PER_PIXEL * height) as usize;
+        let next_id = self.surface_next_id;
+        self.surfaces.insert(
+            next_id,
+            StubSurface {
+                width,
+                buffers: [vec![0u8; fb_size], vec![0u8; fb_size]],
+                buffer_index: 0,
+            },
+        );
+        self.surface_next_id += 1;
+        Ok(next_id)
+    }
+
+    fn release_surface(&mut self, surface_id: u32) {
+        self.surfaces.remove(&surface_id);
+        self.event_devices
+            .retain(|_, (event_surface_id, _)| *event_surface_id != surface_id);
+    }
+
+    fn framebuffer(&self, surface_id: u32) -> Option<GpuDisplayFramebuffer> {
+        let surface = self.get_surface(surface_id)?;
+        let buffer_index = (surface.buffer_index + 1) % BUFFER_COUNT;
+        // Safe because the buffer is owned by `surface` for as long as the returned
+        // `VolatileSlice`'s lifetime, which is tied to `&self`.
+        let framebuffer = unsafe {
+            VolatileSlice::new(
+                surface.buffers[buffer_index].as_ptr() as *mut u8,
+                surface.buffers[buffer_index].len() as u64,
+            )
+        };
+
+        Some(GpuDisplayFramebuffer::new(
+            framebuffer,
+            surface.width * BYTES_PER_PIXEL,
+            BYTES_PER_PIXEL,
+        ))
+    }
+
+    fn next_buffer_in_use(&self, _surface_id: u32) -> bool {
+        false
+    }
+
+    fn flip(&mut self, surface_id: u32) {
+        match self.surfaces.get_mut(&surface_id) {
+            Some(surface) => surface.buffer_index = (surface.buffer_index + 1) % BUFFER_COUNT,
+            None => debug_assert!(false, "invalid surface_id {}", surface_id),
+        }
+    }
+
+    fn flip_to(&mut self, surface_id: u32, _import_id: u32) {
+        self.flip(surface_id)
+    }
+
+    fn commit(&mut self, _surface_id: u32) {}
+
+    fn set_position(&mut self, _surface_id: u32, _x: u32, _y: u32) {}
+
+    fn close_requested(&self, _surface_id: u32) -> bool {
+        false
+    }
+
+    fn import_dmabuf(
+        &mut self,
+        _fd: RawFd,
+        _offset: u32,
+        _stride: u32,
+        _modifiers: u64,
+        width: u32,
+        height: u32,
+        _fourcc: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        let next_id = self.dmabuf_next_id;
+        self.dmabufs.insert(next_id, (width, height));
+        self.dmabuf_next_id += 1;
+        Ok(next_id)
+    }
+
+    fn release_import(&mut self, import_id: u32) {
+        self.dmabufs.remove(&import_id);
+    }
+
+    fn import_event_device(
+        &mut self,
+        event_device: EventDevice,
+        surface_id: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        if self.get_surface(surface_id).is_none() {
+            return Err(GpuDisplayError::InvalidSurfaceId);
+        }
+
+        let next_id = self.event_device_next_id;
+        self.event_devices.insert(next_id, (surface_id, event_device));
+        self.event_device_next_id += 1;
+        Ok(next_id)
+    }
+
+    fn release_event_device(&mut self, event_device_id: u32) {
+        self.event_devices.remove(&event_device_id);
+    }
+
+    fn event_devices(&self) -> Vec<(u32, RawFd)> {
+        self.event_devices
+            .iter()
+            .map(|(&id, (_, event_device))| (id, event_device.as_raw_fd()))
+            .collect()
+    }
+
+    // There is no compositor to generate input from, so there is nothing to dispatch.
+    fn dispatch_events(&mut self) {}
+}
+
+impl AsRawFd for DisplayStub {
+    fn as_raw_fd(&self) -> RawFd {
+        self.event_fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::GpuDisplay;
+
+    #[test]
+    fn stub_surface_lifecycle() {
+        let mut display = GpuDisplay::open_stub().unwrap();
+        let surface_id = display.create_surface(None, 4, 2).unwrap();
+
+        let framebuffer = display.framebuffer_memory(surface_id).unwrap();
+        assert_eq!(framebuffer.size(), (4 * 4 * 2) as u64);
+
+        assert!(!display.next_buffer_in_use(surface_id));
+        display.flip(surface_id);
+        display.commit(surface_id);
+        assert!(!display.close_requested(surface_id));
+
+        display.release_surface(surface_id);
+    }
+}
@@ -0,0 +1,505 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Wayland backend for `DisplayT`, implemented in terms of the `dwl` FFI bindings.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::ptr::null_mut;
+
+use data_model::VolatileMemory;
+use sys_util::{round_up_to_page_size, MemoryMapping, SharedMemory};
+
+use crate::dwl::*;
+use crate::{DisplayT, EventDevice, EventDeviceKind, GpuDisplayError, GpuDisplayFramebuffer};
+
+const BUFFER_COUNT: usize = 2;
+const BYTES_PER_PIXEL: u32 = 4;
+
+const DWL_SEAT_EVENT_KEYBOARD_KEY: u8 = 0;
+const DWL_SEAT_EVENT_POINTER_MOTION: u8 = 1;
+const DWL_SEAT_EVENT_POINTER_BUTTON: u8 = 2;
+
+#[repr(C)]
+struct dwl_seat_event {
+    surface_id: u32,
+    type_: u8,
+    a: i32,
+    b: i32,
+    c: i32,
+    d: i32,
+}
+
+extern "C" {
+    // Pops the next queued pointer/keyboard/touch event recorded by the compositor's seat
+    // listeners, or returns false if none are queued.
+    fn dwl_context_next_seat_event_raw(ctx: *mut dwl_context, out: *mut dwl_seat_event) -> bool;
+}
+
+/// Pops the next queued seat event for `ctx`, if any.
+///
+/// # Safety
+/// `ctx` must be a valid, non-null context pointer.
+unsafe fn dwl_context_next_seat_event(ctx: *mut dwl_context) -> Option<dwl_seat_event> {
+    let mut event = dwl_seat_event {
+        surface_id: 0,
+        type_: 0,
+        a: 0,
+        b: 0,
+        c: 0,
+        d: 0,
+    };
+    if dwl_context_next_seat_event_raw(ctx, &mut event) {
+        Some(event)
+    } else {
+        None
+    }
+}
+
+struct DwlContext(*mut dwl_context);
+impl Drop for DwlContext {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            // Safe given that we checked the pointer for non-null and it should always be of the
+            // correct type.
+            unsafe {
+                dwl_context_destroy(&mut self.0);
+            }
+        }
+    }
+}
+
+struct DwlDmabuf(*mut dwl_dmabuf);
+impl Drop for DwlDmabuf {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            // Safe given that we checked the pointer for non-null and it should always be of the
+            // correct type.
+            unsafe {
+                dwl_dmabuf_destroy(&mut self.0);
+            }
+        }
+    }
+}
+
+struct DwlSurface(*mut dwl_surface);
+impl Drop for DwlSurface {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            // Safe given that we checked the pointer for non-null and it should always be of the
+            // correct type.
+            unsafe {
+                dwl_surface_destroy(&mut self.0);
+            }
+        }
+    }
+}
+
+/// A single seat input event translated out of the Wayland compositor's pointer, keyboard, or
+/// touch listeners, tagged with the surface it was delivered to.
+enum SeatEventKind {
+    KeyboardKey { linux_keycode: u16, pressed: bool },
+    PointerMotion { dx: i32, dy: i32 },
+    PointerButton { linux_button_code: u16, pressed: bool },
+    Touch { slot: i32, tracking_id: i32, x: i32, y: i32 },
+}
+
+struct SeatEvent {
+    surface_id: u32,
+    kind: SeatEventKind,
+}
+
+impl SeatEvent {
+    /// Returns the kind of `EventDevice` this event should be forwarded to.
+    fn device_kind(&self) -> EventDeviceKind {
+        match self.kind {
+            SeatEventKind::KeyboardKey { .. } => EventDeviceKind::Keyboard,
+            SeatEventKind::PointerMotion { .. } | SeatEventKind::PointerButton { .. } => {
+                EventDeviceKind::Mouse
+            }
+            SeatEventKind::Touch { .. } => EventDeviceKind::Touchscreen,
+        }
+    }
+
+    fn dispatch(&self, event_device: &mut EventDevice) -> std::io::Result<()> {
+        match self.kind {
+            SeatEventKind::KeyboardKey {
+                linux_keycode,
+                pressed,
+            } => event_device.send_key_event(linux_keycode, pressed),
+            SeatEventKind::PointerMotion { dx, dy } => event_device.send_motion_event(dx, dy),
+            SeatEventKind::PointerButton {
+                linux_button_code,
+                pressed,
+            } => event_device.send_button_event(linux_button_code, pressed),
+            SeatEventKind::Touch {
+                slot,
+                tracking_id,
+                x,
+                y,
+            } => event_device.send_touch_event(slot, tracking_id, x, y),
+        }
+    }
+}
+
+struct DisplayWlSurface {
+    surface: DwlSurface,
+    row_size: u32,
+    buffer_size: usize,
+    buffer_index: Cell<usize>,
+    buffer_mem: MemoryMapping,
+}
+
+impl DisplayWlSurface {
+    fn surface(&self) -> *mut dwl_surface {
+        self.surface.0
+    }
+}
+
+/// A connection to a Wayland compositor and associated collection of state.
+pub struct DisplayWl {
+    ctx: DwlContext,
+    dmabufs: HashMap<u32, DwlDmabuf>,
+    dmabuf_next_id: u32,
+    surfaces: HashMap<u32, DisplayWlSurface>,
+    surface_next_id: u32,
+    event_devices: HashMap<u32, (u32, EventDevice)>,
+    event_device_next_id: u32,
+}
+
+impl DisplayWl {
+    /// Opens a fresh connection to the compositor.
+    pub fn new<P: AsRef<Path>>(wayland_path: P) -> Result<DisplayWl, GpuDisplayError> {
+        // The dwl_context_new call should always be safe to call, and we check its result.
+        let ctx = DwlContext(unsafe { dwl_context_new() });
+        if ctx.0.is_null() {
+            return Err(GpuDisplayError::Allocate);
+        }
+
+        // The dwl_context_setup call is always safe to call given that the supplied context is
+        // valid. and we check its result.
+        let cstr_path = match wayland_path.as_ref().as_os_str().to_str() {
+            Some(str) => match CString::new(str) {
+                Ok(cstr) => cstr,
+                Err(_) => return Err(GpuDisplayError::InvalidPath),
+            },
+            None => return Err(GpuDisplayError::InvalidPath),
+        };
+        let setup_success = unsafe { dwl_context_setup(ctx.0, cstr_path.as_ptr()) };
+        if !setup_success {
+            return Err(GpuDisplayError::Connect);
+        }
+
+        Ok(DisplayWl {
+            ctx,
+            dmabufs: Default::default(),
+            dmabuf_next_id: 0,
+            surfaces: Default::default(),
+            surface_next_id: 0,
+            event_devices: Default::default(),
+            event_device_next_id: 0,
+        })
+    }
+
+    fn ctx(&self) -> *mut dwl_context {
+        self.ctx.0
+    }
+
+    fn get_surface(&self, surface_id: u32) -> Option<&DisplayWlSurface> {
+        self.surfaces.get(&surface_id)
+    }
+
+    /// Pulls the next input event queued by the compositor's pointer, keyboard, and touch seat
+    /// listeners, if any, translating it into a backend-independent `SeatEvent`.
+    fn next_seat_event(&self) -> Option<SeatEvent> {
+        // Safe given that the context pointer is valid. `dwl_context_next_seat_event` hands back
+        // plain integer fields describing the event, which are translated below.
+        let raw = unsafe { dwl_context_next_seat_event(self.ctx()) }?;
+        let kind = match raw.type_ {
+            DWL_SEAT_EVENT_KEYBOARD_KEY => SeatEventKind::KeyboardKey {
+                linux_keycode: raw.a as u16,
+                pressed: raw.b != 0,
+            },
+            DWL_SEAT_EVENT_POINTER_MOTION => SeatEventKind::PointerMotion {
+                dx: raw.a,
+                dy: raw.b,
+            },
+            DWL_SEAT_EVENT_POINTER_BUTTON => SeatEventKind::PointerButton {
+                linux_button_code: raw.a as u16,
+                pressed: raw.b != 0,
+            },
+            _ => SeatEventKind::Touch {
+                slot: raw.a,
+                tracking_id: raw.b,
+                x: raw.c,
+                y: raw.d,
+            },
+        };
+        Some(SeatEvent {
+            surface_id: raw.surface_id,
+            kind,
+        })
+    }
+}
+
+impl DisplayT for DisplayWl {
+    fn create_surface(
+        &mut self,
+        parent_surface_id: Option<u32>,
+        width: u32,
+        height: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        let parent_ptr = match parent_surface_id {
+            Some(id) => match self.get_surface(id).map(|p| p.surface()) {
+                Some(ptr) => ptr,
+                None => return Err(GpuDisplayError::InvalidSurfaceId),
+            },
+            None => null_mut(),
+        };
+        let row_size = width * BYTES_PER_PIXEL;
+        let fb_size = row_size * height;
+        let buffer_size = round_up_to_page_size(fb_size as usize * BUFFER_COUNT);
+        let mut buffer_shm = SharedMemory::new(Some(
+            CStr::from_bytes_with_nul(b"GpuDisplaySurface\0").unwrap(),
+        ))
+        .map_err(GpuDisplayError::CreateShm)?;
+        buffer_shm
+            .set_size(buffer_size as u64)
+            .map_err(GpuDisplayError::SetSize)?;
+        let buffer_mem = MemoryMapping::from_fd(&buffer_shm, buffer_size).unwrap();
+
+        // Safe because only a valid context, parent pointer (if not  None), and buffer FD are used.
+        // The returned surface is checked for validity before being filed away.
+        let surface = DwlSurface(unsafe {
+            dwl_context_surface_new(
+                self.ctx(),
+                parent_ptr,
+                buffer_shm.as_raw_fd(),
+                buffer_size,
+                fb_size as usize,
+                width,
+                height,
+                row_size,
+            )
+        });
+
+        if surface.0.is_null() {
+            return Err(GpuDisplayError::CreateSurface);
+        }
+
+        let next_id = self.surface_next_id;
+        self.surfaces.insert(
+            next_id,
+            DisplayWlSurface {
+                surface,
+                row_size,
+                buffer_size: fb_size as usize,
+                buffer_index: Cell::new(0),
+                buffer_mem,
+            },
+        );
+
+        self.surface_next_id += 1;
+        Ok(next_id)
+    }
+
+    fn release_surface(&mut self, surface_id: u32) {
+        self.surfaces.remove(&surface_id);
+        self.event_devices
+            .retain(|_, (event_surface_id, _)| *event_surface_id != surface_id);
+    }
+
+    fn framebuffer(&self, surface_id: u32) -> Option<GpuDisplayFramebuffer> {
+        let surface = self.get_surface(surface_id)?;
+        let buffer_index = (surface.buffer_index.get() + 1) % BUFFER_COUNT;
+        let framebuffer = surface
+            .buffer_mem
+            .get_slice(
+                (buffer_index * surface.buffer_size) as u64,
+                surface.buffer_size as u64,
+            )
+            .ok()?;
+
+        Some(GpuDisplayFramebuffer::new(
+            framebuffer,
+            surface.row_size,
+            BYTES_PER_PIXEL,
+        ))
+    }
+
+    fn next_buffer_in_use(&self, surface_id: u32) -> bool {
+        match self.get_surface(surface_id) {
+            Some(surface) => {
+                let next_buffer_index = (surface.buffer_index.get() + 1) % BUFFER_COUNT;
+                // Safe because only a valid surface and buffer index is used.
+                unsafe { dwl_surface_buffer_in_use(surface.surface(), next_buffer_index) }
+            }
+            None => {
+                debug_assert!(false, "invalid surface_id {}", surface_id);
+                false
+            }
+        }
+    }
+
+    fn flip(&mut self, surface_id: u32) {
+        match self.get_surface(surface_id) {
+            Some(surface) => {
+                surface
+                    .buffer_index
+                    .set((surface.buffer_index.get() + 1) % BUFFER_COUNT);
+                // Safe because only a valid surface and buffer index is used.
+                unsafe {
+                    dwl_surface_flip(surface.surface(), surface.buffer_index.get());
+                }
+            }
+            None => debug_assert!(false, "invalid surface_id {}", surface_id),
+        }
+    }
+
+    fn flip_to(&mut self, surface_id: u32, import_id: u32) {
+        match self.get_surface(surface_id) {
+            Some(surface) => {
+                match self.dmabufs.get(&import_id) {
+                    // Safe because only a valid surface and dmabuf is used.
+                    Some(dmabuf) => unsafe { dwl_surface_flip_to(surface.surface(), dmabuf.0) },
+                    None => debug_assert!(false, "invalid import_id {}", import_id),
+                }
+            }
+            None => debug_assert!(false, "invalid surface_id {}", surface_id),
+        }
+    }
+
+    fn commit(&mut self, surface_id: u32) {
+        match self.get_surface(surface_id) {
+            Some(surface) => {
+                // Safe because only a valid surface is used.
+                unsafe {
+                    dwl_surface_commit(surface.surface());
+                }
+            }
+            None => debug_assert!(false, "invalid surface_id {}", surface_id),
+        }
+    }
+
+    fn set_position(&mut self, surface_id: u32, x: u32, y: u32) {
+        match self.get_surface(surface_id) {
+            Some(surface) => {
+                // Safe because only a valid surface is used.
+                unsafe {
+                    dwl_surface_set_position(surface.surface(), x, y);
+                }
+            }
+            None => debug_assert!(false, "invalid surface_id {}", surface_id),
+        }
+    }
+
+    fn close_requested(&self, surface_id: u32) -> bool {
+        match self.get_surface(surface_id) {
+            Some(surface) =>
+            // Safe because only a valid surface is used.
+            unsafe { dwl_surface_close_requested(surface.surface()) }
+            None => false,
+        }
+    }
+
+    fn import_dmabuf(
+        &mut self,
+        fd: RawFd,
+        offset: u32,
+        stride: u32,
+        modifiers: u64,
+        width: u32,
+        height: u32,
+        fourcc: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        // Safe given that the context pointer is valid. Any other invalid parameters would be
+        // rejected by dwl_context_dmabuf_new safely. We check that the resulting dmabuf is valid
+        // before filing it away.
+        let dmabuf = DwlDmabuf(unsafe {
+            dwl_context_dmabuf_new(
+                self.ctx(),
+                fd,
+                offset,
+                stride,
+                modifiers,
+                width,
+                height,
+                fourcc,
+            )
+        });
+        if dmabuf.0.is_null() {
+            return Err(GpuDisplayError::FailedImport);
+        }
+
+        let next_id = self.dmabuf_next_id;
+        self.dmabufs.insert(next_id, dmabuf);
+        self.dmabuf_next_id += 1;
+        Ok(next_id)
+    }
+
+    fn release_import(&mut self, import_id: u32) {
+        self.dmabufs.remove(&import_id);
+    }
+
+    fn import_event_device(
+        &mut self,
+        event_device: EventDevice,
+        surface_id: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        if self.get_surface(surface_id).is_none() {
+            return Err(GpuDisplayError::InvalidSurfaceId);
+        }
+
+        let next_id = self.event_device_next_id;
+        self.event_devices.insert(next_id, (surface_id, event_device));
+        self.event_device_next_id += 1;
+        Ok(next_id)
+    }
+
+    fn release_event_device(&mut self, event_device_id: u32) {
+        self.event_devices.remove(&event_device_id);
+    }
+
+    fn event_devices(&self) -> Vec<(u32, RawFd)> {
+        self.event_devices
+            .iter()
+            .map(|(&id, (_, event_device))| (id, event_device.as_raw_fd()))
+            .collect()
+    }
+
+    fn dispatch_events(&mut self) {
+        // Safe given that the context pointer is valid.
+        unsafe {
+            dwl_context_dispatch(self.ctx());
+        }
+
+        // Drain seat events queued by the Wayland compositor and forward each one to the event
+        // devices registered against its surface.
+        while let Some(seat_event) = self.next_seat_event() {
+            let device_kind = seat_event.device_kind();
+            for (_, event_device) in self.event_devices.values_mut().filter(|(surface_id, event_device)| {
+                *surface_id == seat_event.surface_id && event_device.kind() == device_kind
+            }) {
+                let _ = seat_event.dispatch(event_device);
+            }
+        }
+    }
+}
+
+impl Drop for DisplayWl {
+    fn drop(&mut self) {
+        // Safe given that the context pointer is valid.
+        unsafe { dwl_context_destroy(&mut self.ctx.0) }
+    }
+}
+
+impl AsRawFd for DisplayWl {
+    fn as_raw_fd(&self) -> RawFd {
+        // Safe given that the context pointer is valid.
+        unsafe { dwl_context_fd(self.ctx.0) }
+    }
+}
@@ -0,0 +1,425 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! X11 backend for `DisplayT`.
+//!
+//! Surfaces are top-level windows and are presented by attaching each buffer of a shared memory
+//! file to the server as a MIT-SHM segment and calling `xcb_shm_put_image` on `flip`, mirroring
+//! the double buffering the Wayland backend does with `BUFFER_COUNT`.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr::null_mut;
+
+use data_model::VolatileMemory;
+use sys_util::{round_up_to_page_size, MemoryMapping, SharedMemory};
+
+use crate::x11::*;
+use crate::{DisplayT, EventDevice, EventDeviceKind, GpuDisplayError, GpuDisplayFramebuffer};
+
+const BUFFER_COUNT: usize = 2;
+const BYTES_PER_PIXEL: u32 = 4;
+
+/// The geometry and backing fd of a dmabuf imported via `import_dmabuf`.
+struct X11Dmabuf {
+    fd: RawFd,
+    stride: u32,
+    width: u32,
+    height: u32,
+}
+
+/// A `RawFd` that does not own the descriptor it wraps, so it can be handed to
+/// `MemoryMapping::from_fd` without taking ownership away from the caller of `import_dmabuf`.
+struct BorrowedFd(RawFd);
+impl AsRawFd for BorrowedFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+struct X11Window {
+    window: XcbWindow,
+    shm_segs: [XcbShmSeg; BUFFER_COUNT],
+    row_size: u32,
+    buffer_size: usize,
+    buffer_index: Cell<usize>,
+    buffer_mem: MemoryMapping,
+    close_requested: Cell<bool>,
+}
+
+impl X11Window {
+    fn buffer_offset(&self, index: usize) -> u64 {
+        (index * self.buffer_size) as u64
+    }
+}
+
+/// Returns the kind of `EventDevice` a non-`CloseRequested` `XcbEvent` should be forwarded to.
+fn input_event_device_kind(event: &XcbEvent) -> EventDeviceKind {
+    match event {
+        XcbEvent::KeyboardKey { .. } => EventDeviceKind::Keyboard,
+        XcbEvent::PointerMotion { .. } | XcbEvent::PointerButton { .. } => EventDeviceKind::Mouse,
+        XcbEvent::Touch { .. } => EventDeviceKind::Touchscreen,
+        XcbEvent::CloseRequested => unreachable!(),
+    }
+}
+
+impl Drop for X11Window {
+    fn drop(&mut self) {
+        for seg in self.shm_segs.iter() {
+            // Safe because the connection and segment are both valid for the lifetime of the
+            // window.
+            unsafe { xcb_shm_detach(*seg) };
+        }
+        // Safe because the window handle is valid for the lifetime of the window.
+        unsafe { xcb_destroy_window(self.window) };
+    }
+}
+
+/// A connection to an X11 server and associated collection of state.
+pub struct DisplayX11 {
+    connection: XcbConnection,
+    dmabufs: HashMap<u32, X11Dmabuf>,
+    dmabuf_next_id: u32,
+    windows: HashMap<u32, X11Window>,
+    window_next_id: u32,
+    event_devices: HashMap<u32, (u32, EventDevice)>,
+    event_device_next_id: u32,
+}
+
+impl DisplayX11 {
+    /// Opens a fresh connection to the X11 server named by the `DISPLAY` environment variable.
+    pub fn new() -> Result<DisplayX11, GpuDisplayError> {
+        // Safe because the returned connection is checked for validity before use.
+        let connection = unsafe { xcb_connect(null_mut()) };
+        if connection.is_null() {
+            return Err(GpuDisplayError::Connect);
+        }
+
+        Ok(DisplayX11 {
+            connection,
+            dmabufs: Default::default(),
+            dmabuf_next_id: 0,
+            windows: Default::default(),
+            window_next_id: 0,
+            event_devices: Default::default(),
+            event_device_next_id: 0,
+        })
+    }
+
+    fn get_window(&self, surface_id: u32) -> Option<&X11Window> {
+        self.windows.get(&surface_id)
+    }
+}
+
+impl DisplayT for DisplayX11 {
+    fn create_surface(
+        &mut self,
+        _parent_surface_id: Option<u32>,
+        width: u32,
+        height: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        let row_size = width * BYTES_PER_PIXEL;
+        let fb_size = (row_size * height) as usize;
+        let buffer_size = round_up_to_page_size(fb_size * BUFFER_COUNT);
+        let mut buffer_shm = SharedMemory::new(None).map_err(GpuDisplayError::CreateShm)?;
+        buffer_shm
+            .set_size(buffer_size as u64)
+            .map_err(GpuDisplayError::SetSize)?;
+        let buffer_mem = MemoryMapping::from_fd(&buffer_shm, buffer_size).unwrap();
+
+        // Safe because the connection is valid and the window is checked for validity before
+        // being filed away.
+        let window = unsafe { xcb_create_window(self.connection, width, height) };
+        if window == 0 {
+            return Err(GpuDisplayError::CreateSurface);
+        }
+
+        // Subscribe to WM_DELETE_WINDOW client messages so `close_requested` can be answered
+        // without blocking on the window manager to destroy us.
+        // Safe because the window handle is valid.
+        unsafe { xcb_enable_window_delete_notify(self.connection, window) };
+
+        let mut shm_segs = [0; BUFFER_COUNT];
+        for index in 0..BUFFER_COUNT {
+            // Safe because the connection and shared memory FD are both valid, and the segment
+            // attachment is only used after a successful attach.
+            let seg = unsafe {
+                xcb_shm_attach_fd(self.connection, buffer_shm.as_raw_fd(), fb_size, index)
+            };
+            if seg == 0 {
+                // Safe because only the segments that were successfully attached above are
+                // detached.
+                unsafe {
+                    for seg in &shm_segs[..index] {
+                        xcb_shm_detach(*seg);
+                    }
+                }
+                return Err(GpuDisplayError::CreateSurface);
+            }
+            shm_segs[index] = seg;
+        }
+
+        self.windows.insert(
+            self.window_next_id,
+            X11Window {
+                window,
+                shm_segs,
+                row_size,
+                buffer_size: fb_size,
+                buffer_index: Cell::new(0),
+                buffer_mem,
+                close_requested: Cell::new(false),
+            },
+        );
+
+        let next_id = self.window_next_id;
+        self.window_next_id += 1;
+        Ok(next_id)
+    }
+
+    fn release_surface(&mut self, surface_id: u32) {
+        self.windows.remove(&surface_id);
+        self.event_devices
+            .retain(|_, (event_surface_id, _)| *event_surface_id != surface_id);
+    }
+
+    fn framebuffer(&self, surface_id: u32) -> Option<GpuDisplayFramebuffer> {
+        let window = self.get_window(surface_id)?;
+        let buffer_index = (window.buffer_index.get() + 1) % BUFFER_COUNT;
+        let framebuffer = window
+            .buffer_mem
+            .get_slice(window.buffer_offset(buffer_index), window.buffer_size as u64)
+            .ok()?;
+
+        Some(GpuDisplayFramebuffer::new(
+            framebuffer,
+            window.row_size,
+            BYTES_PER_PIXEL,
+        ))
+    }
+
+    fn next_buffer_in_use(&self, surface_id: u32) -> bool {
+        match self.get_window(surface_id) {
+            Some(window) => {
+                let next_index = (window.buffer_index.get() + 1) % BUFFER_COUNT;
+                // Safe because only a valid connection and shm segment is used.
+                unsafe { xcb_shm_seg_busy(self.connection, window.shm_segs[next_index]) }
+            }
+            None => {
+                debug_assert!(false, "invalid surface_id {}", surface_id);
+                false
+            }
+        }
+    }
+
+    fn flip(&mut self, surface_id: u32) {
+        match self.get_window(surface_id) {
+            Some(window) => {
+                window
+                    .buffer_index
+                    .set((window.buffer_index.get() + 1) % BUFFER_COUNT);
+                let seg = window.shm_segs[window.buffer_index.get()];
+                // Safe because only a valid connection, window and shm segment is used.
+                unsafe { xcb_shm_put_image(self.connection, window.window, seg) };
+            }
+            None => debug_assert!(false, "invalid surface_id {}", surface_id),
+        }
+    }
+
+    fn flip_to(&mut self, surface_id: u32, import_id: u32) {
+        let dmabuf = match self.dmabufs.get(&import_id) {
+            Some(dmabuf) => dmabuf,
+            None => return debug_assert!(false, "invalid import_id {}", import_id),
+        };
+        let window = match self.windows.get(&surface_id) {
+            Some(window) => window,
+            None => return debug_assert!(false, "invalid surface_id {}", surface_id),
+        };
+
+        // Safe because only a valid connection, window, and dmabuf fd are used.
+        let imported = unsafe {
+            xcb_dri3_pixmap_put(
+                self.connection,
+                window.window,
+                dmabuf.fd,
+                dmabuf.stride,
+                dmabuf.width,
+                dmabuf.height,
+            )
+        };
+        if imported {
+            return;
+        }
+
+        // DRI3 pixmap import is unavailable, so fall back to a CPU copy of the dmabuf's contents
+        // into the window's next shm buffer before presenting it.
+        let copy_size = (dmabuf.stride * dmabuf.height) as usize;
+        // Safe because `fd` references a dmabuf at least `copy_size` bytes long, and the mapping
+        // is only read from below.
+        let dmabuf_mem = match MemoryMapping::from_fd(&BorrowedFd(dmabuf.fd), copy_size) {
+            Ok(mem) => mem,
+            Err(_) => return,
+        };
+        let next_index = (window.buffer_index.get() + 1) % BUFFER_COUNT;
+        let copy_len = copy_size.min(window.buffer_size);
+        if let (Ok(src), Ok(dst)) = (
+            dmabuf_mem.get_slice(0, copy_len as u64),
+            window
+                .buffer_mem
+                .get_slice(window.buffer_offset(next_index), copy_len as u64),
+        ) {
+            // Safe because `src` and `dst` are both valid for `copy_len` bytes and do not
+            // overlap: they come from separate mappings.
+            unsafe {
+                std::ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr(), copy_len);
+            }
+        }
+        window.buffer_index.set(next_index);
+
+        let seg = window.shm_segs[window.buffer_index.get()];
+        // Safe because only a valid connection, window and shm segment is used.
+        unsafe { xcb_shm_put_image(self.connection, window.window, seg) };
+    }
+
+    fn commit(&mut self, _surface_id: u32) {
+        // Safe because the connection is always valid for the lifetime of `DisplayX11`.
+        unsafe { xcb_flush(self.connection) };
+    }
+
+    fn set_position(&mut self, surface_id: u32, x: u32, y: u32) {
+        match self.get_window(surface_id) {
+            Some(window) => {
+                // Safe because only a valid connection and window is used.
+                unsafe { xcb_configure_window_position(self.connection, window.window, x, y) };
+            }
+            None => debug_assert!(false, "invalid surface_id {}", surface_id),
+        }
+    }
+
+    fn close_requested(&self, surface_id: u32) -> bool {
+        match self.get_window(surface_id) {
+            Some(window) => window.close_requested.get(),
+            None => false,
+        }
+    }
+
+    fn import_dmabuf(
+        &mut self,
+        fd: RawFd,
+        _offset: u32,
+        stride: u32,
+        _modifiers: u64,
+        width: u32,
+        height: u32,
+        _fourcc: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        let next_id = self.dmabuf_next_id;
+        self.dmabufs.insert(
+            next_id,
+            X11Dmabuf {
+                fd,
+                stride,
+                width,
+                height,
+            },
+        );
+        self.dmabuf_next_id += 1;
+        Ok(next_id)
+    }
+
+    fn release_import(&mut self, import_id: u32) {
+        self.dmabufs.remove(&import_id);
+    }
+
+    fn import_event_device(
+        &mut self,
+        event_device: EventDevice,
+        surface_id: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        if self.get_window(surface_id).is_none() {
+            return Err(GpuDisplayError::InvalidSurfaceId);
+        }
+
+        let next_id = self.event_device_next_id;
+        self.event_devices.insert(next_id, (surface_id, event_device));
+        self.event_device_next_id += 1;
+        Ok(next_id)
+    }
+
+    fn release_event_device(&mut self, event_device_id: u32) {
+        self.event_devices.remove(&event_device_id);
+    }
+
+    fn event_devices(&self) -> Vec<(u32, RawFd)> {
+        self.event_devices
+            .iter()
+            .map(|(&id, (_, event_device))| (id, event_device.as_raw_fd()))
+            .collect()
+    }
+
+    fn dispatch_events(&mut self) {
+        // Safe because the connection is always valid for the lifetime of `DisplayX11`. Each
+        // event is translated and, for a WM_DELETE_WINDOW ClientMessage, marks the owning window
+        // as close-requested; keyboard, pointer, and touch events are forwarded to any event
+        // devices registered against the window that received them.
+        while let Some((x_window, event)) = unsafe { xcb_poll_for_event(self.connection) } {
+            // Events carry the X window handle; map it back to the ID the window is filed under.
+            let window_id = match self.windows.iter().find(|(_, w)| w.window == x_window) {
+                Some((&id, _)) => id,
+                None => continue,
+            };
+            match event {
+                XcbEvent::CloseRequested => {
+                    if let Some(window) = self.windows.get(&window_id) {
+                        window.close_requested.set(true);
+                    }
+                }
+                input_event => {
+                    let device_kind = input_event_device_kind(&input_event);
+                    for (_, event_device) in self.event_devices.values_mut().filter(
+                        |(surface_id, event_device)| {
+                            *surface_id == window_id && event_device.kind() == device_kind
+                        },
+                    ) {
+                        let _ = match input_event {
+                            XcbEvent::KeyboardKey {
+                                linux_keycode,
+                                pressed,
+                            } => event_device.send_key_event(linux_keycode, pressed),
+                            XcbEvent::PointerMotion { dx, dy } => {
+                                event_device.send_motion_event(dx, dy)
+                            }
+                            XcbEvent::PointerButton {
+                                linux_button_code,
+                                pressed,
+                            } => event_device.send_button_event(linux_button_code, pressed),
+                            XcbEvent::Touch {
+                                slot,
+                                tracking_id,
+                                x,
+                                y,
+                            } => event_device.send_touch_event(slot, tracking_id, x, y),
+                            XcbEvent::CloseRequested => unreachable!(),
+                        };
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for DisplayX11 {
+    fn drop(&mut self) {
+        // Safe given that the connection pointer is valid.
+        unsafe { xcb_disconnect(self.connection) }
+    }
+}
+
+impl AsRawFd for DisplayX11 {
+    fn as_raw_fd(&self) -> RawFd {
+        // Safe given that the connection pointer is valid.
+        unsafe { xcb_get_file_descriptor(self.connection) }
+    }
+}
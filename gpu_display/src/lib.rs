@@ -2,25 +2,27 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-//! Crate for displaying simple surfaces and GPU buffers over wayland.
+//! Crate for displaying simple surfaces and GPU buffers over a backend-specific display server.
 
 mod dwl;
+mod event_device;
+mod gpu_display_stub;
+mod gpu_display_wl;
+mod gpu_display_x11;
+mod x11;
 
-use std::cell::Cell;
-use std::collections::HashMap;
-use std::ffi::{CStr, CString};
 use std::fmt::{self, Display};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
-use std::ptr::null_mut;
 
-use data_model::{VolatileMemory, VolatileSlice};
-use sys_util::{round_up_to_page_size, Error as SysError, MemoryMapping, SharedMemory};
+use data_model::VolatileSlice;
+use sys_util::Error as SysError;
 
-use crate::dwl::*;
+pub use crate::event_device::{EventDevice, EventDeviceKind};
 
-const BUFFER_COUNT: usize = 2;
-const BYTES_PER_PIXEL: u32 = 4;
+use crate::gpu_display_stub::DisplayStub;
+use crate::gpu_display_wl::DisplayWl;
+use crate::gpu_display_x11::DisplayX11;
 
 /// An error generated by `GpuDisplay`.
 #[derive(Debug)]
@@ -60,108 +62,161 @@ impl Display for GpuDisplayError {
     }
 }
 
-struct DwlContext(*mut dwl_context);
-impl Drop for DwlContext {
-    fn drop(&mut self) {
-        if !self.0.is_null() {
-            // Safe given that we checked the pointer for non-null and it should always be of the
-            // correct type.
-            unsafe {
-                dwl_context_destroy(&mut self.0);
-            }
-        }
-    }
+/// A buffer that is currently writable by the caller, along with the layout needed to address
+/// individual pixels within it.
+pub struct GpuDisplayFramebuffer<'a> {
+    framebuffer: VolatileSlice<'a>,
+    stride: u32,
+    bytes_per_pixel: u32,
 }
 
-struct DwlDmabuf(*mut dwl_dmabuf);
-impl Drop for DwlDmabuf {
-    fn drop(&mut self) {
-        if !self.0.is_null() {
-            // Safe given that we checked the pointer for non-null and it should always be of the
-            // correct type.
-            unsafe {
-                dwl_dmabuf_destroy(&mut self.0);
-            }
+impl<'a> GpuDisplayFramebuffer<'a> {
+    fn new(
+        framebuffer: VolatileSlice<'a>,
+        stride: u32,
+        bytes_per_pixel: u32,
+    ) -> GpuDisplayFramebuffer<'a> {
+        GpuDisplayFramebuffer {
+            framebuffer,
+            stride,
+            bytes_per_pixel,
         }
     }
-}
 
-struct DwlSurface(*mut dwl_surface);
-impl Drop for DwlSurface {
-    fn drop(&mut self) {
-        if !self.0.is_null() {
-            // Safe given that we checked the pointer for non-null and it should always be of the
-            // correct type.
-            unsafe {
-                dwl_surface_destroy(&mut self.0);
-            }
-        }
+    /// Returns the slice of memory that backs this framebuffer.
+    pub fn as_volatile_slice(&self) -> VolatileSlice<'a> {
+        self.framebuffer
     }
-}
 
-struct GpuDisplaySurface {
-    surface: DwlSurface,
-    buffer_size: usize,
-    buffer_index: Cell<usize>,
-    buffer_mem: MemoryMapping,
-}
+    /// Returns the number of bytes between the start of one row of pixels and the next.
+    pub fn stride(&self) -> u32 {
+        self.stride
+    }
 
-impl GpuDisplaySurface {
-    fn surface(&self) -> *mut dwl_surface {
-        self.surface.0
+    /// Returns the number of bytes used to encode a single pixel.
+    pub fn bytes_per_pixel(&self) -> u32 {
+        self.bytes_per_pixel
     }
 }
 
-/// A connection to the compositor and associated collection of state.
+/// The set of operations that a display output backend must provide, independent of the
+/// particular display server or compositor protocol in use.
 ///
-/// The user of `GpuDisplay` can use `AsRawFd` to poll on the compositor connection's file
-/// descriptor. When the connection is readable, `dispatch_events` can be called to process it.
+/// Implementors keep their own mapping of opaque surface and import IDs to whatever backend
+/// resources those IDs describe, and `GpuDisplay` deals only in those IDs.
+trait DisplayT: AsRawFd {
+    /// Creates a surface on the the compositor as either a top level window, or child of another
+    /// surface, returning a handle to the new surface.
+    fn create_surface(
+        &mut self,
+        parent_surface_id: Option<u32>,
+        width: u32,
+        height: u32,
+    ) -> Result<u32, GpuDisplayError>;
+
+    /// Releases a previously created surface identified by the given handle.
+    fn release_surface(&mut self, surface_id: u32);
+
+    /// Gets a reference to an unused framebuffer for the identified surface.
+    fn framebuffer(&self, surface_id: u32) -> Option<GpuDisplayFramebuffer>;
+
+    /// Returns true if the next buffer in the buffer queue for the given surface is currently in
+    /// use.
+    ///
+    /// If the next buffer is in use, the memory returned from `framebuffer` should not be written
+    /// to.
+    fn next_buffer_in_use(&self, surface_id: u32) -> bool;
+
+    /// Changes the visible contents of the identified surface to the contents of the framebuffer
+    /// last returned by `framebuffer` for this surface.
+    fn flip(&mut self, surface_id: u32);
+
+    /// Changes the visible contents of the identified surface to that of the identified imported
+    /// buffer.
+    fn flip_to(&mut self, surface_id: u32, import_id: u32);
+
+    /// Commits any pending state for the identified surface.
+    fn commit(&mut self, surface_id: u32);
+
+    /// Sets the position of the identified subsurface relative to its parent.
+    ///
+    /// The change in position will not be visible until `commit` is called for the parent
+    /// surface.
+    fn set_position(&mut self, surface_id: u32, x: u32, y: u32);
+
+    /// Returns true if the identified top level surface has been told to close by the
+    /// compositor, and by extension the user.
+    fn close_requested(&self, surface_id: u32) -> bool;
+
+    /// Imports a dmabuf to the compositor for use as a surface buffer and returns a handle to it.
+    fn import_dmabuf(
+        &mut self,
+        fd: RawFd,
+        offset: u32,
+        stride: u32,
+        modifiers: u64,
+        width: u32,
+        height: u32,
+        fourcc: u32,
+    ) -> Result<u32, GpuDisplayError>;
+
+    /// Releases a previously imported dmabuf identified by the given handle.
+    fn release_import(&mut self, import_id: u32);
+
+    /// Registers `event_device` as the input sink for the identified surface, returning a handle
+    /// to it.
+    fn import_event_device(
+        &mut self,
+        event_device: EventDevice,
+        surface_id: u32,
+    ) -> Result<u32, GpuDisplayError>;
+
+    /// Releases a previously imported event device identified by the given handle.
+    fn release_event_device(&mut self, event_device_id: u32);
+
+    /// Returns the file descriptors of all currently registered event devices, so the outer event
+    /// loop can poll them for backpressure.
+    fn event_devices(&self) -> Vec<(u32, RawFd)>;
+
+    /// Dispatches internal events that were received from the backend since the last call to
+    /// `dispatch_events`, including forwarding any translated input to registered event devices.
+    fn dispatch_events(&mut self);
+}
+
+/// A connection to a display server and associated collection of state.
+///
+/// `GpuDisplay` is a thin wrapper around a backend-specific implementation of `DisplayT`, chosen
+/// when the connection is opened. The user of `GpuDisplay` can use `AsRawFd` to poll on the
+/// backend connection's file descriptor. When the connection is readable, `dispatch_events` can
+/// be called to process it.
 pub struct GpuDisplay {
-    ctx: DwlContext,
-    dmabufs: HashMap<u32, DwlDmabuf>,
-    dmabuf_next_id: u32,
-    surfaces: HashMap<u32, GpuDisplaySurface>,
-    surface_next_id: u32,
+    inner: Box<dyn DisplayT>,
 }
 
 impl GpuDisplay {
-    /// Opens a fresh connection to the compositor.
-    pub fn new<P: AsRef<Path>>(wayland_path: P) -> Result<GpuDisplay, GpuDisplayError> {
-        // The dwl_context_new call should always be safe to call, and we check its result.
-        let ctx = DwlContext(unsafe { dwl_context_new() });
-        if ctx.0.is_null() {
-            return Err(GpuDisplayError::Allocate);
-        }
-
-        // The dwl_context_setup call is always safe to call given that the supplied context is
-        // valid. and we check its result.
-        let cstr_path = match wayland_path.as_ref().as_os_str().to_str() {
-            Some(str) => match CString::new(str) {
-                Ok(cstr) => cstr,
-                Err(_) => return Err(GpuDisplayError::InvalidPath),
-            },
-            None => return Err(GpuDisplayError::InvalidPath),
-        };
-        let setup_success = unsafe { dwl_context_setup(ctx.0, cstr_path.as_ptr()) };
-        if !setup_success {
-            return Err(GpuDisplayError::Connect);
-        }
-
+    /// Opens a fresh connection to a Wayland compositor.
+    pub fn open_wayland<P: AsRef<Path>>(wayland_path: P) -> Result<GpuDisplay, GpuDisplayError> {
+        let display_wl = DisplayWl::new(wayland_path)?;
         Ok(GpuDisplay {
-            ctx,
-            dmabufs: Default::default(),
-            dmabuf_next_id: 0,
-            surfaces: Default::default(),
-            surface_next_id: 0,
+            inner: Box::new(display_wl),
         })
     }
 
-    fn ctx(&self) -> *mut dwl_context {
-        self.ctx.0
+    /// Opens a fresh connection to the X11 server named by the `DISPLAY` environment variable.
+    pub fn open_x11() -> Result<GpuDisplay, GpuDisplayError> {
+        let display_x11 = DisplayX11::new()?;
+        Ok(GpuDisplay {
+            inner: Box::new(display_x11),
+        })
     }
 
-    fn get_surface(&self, surface_id: u32) -> Option<&GpuDisplaySurface> {
-        self.surfaces.get(&surface_id)
+    /// Opens a headless display backed by in-memory framebuffers, with no real compositor or
+    /// display server. Useful for automated tests, CI, and headless hosts.
+    pub fn open_stub() -> Result<GpuDisplay, GpuDisplayError> {
+        let display_stub = DisplayStub::new()?;
+        Ok(GpuDisplay {
+            inner: Box::new(display_stub),
+        })
     }
 
     /// Imports a dmabuf to the compositor for use as a surface buffer and returns a handle to it.
@@ -175,43 +230,19 @@ impl GpuDisplay {
         height: u32,
         fourcc: u32,
     ) -> Result<u32, GpuDisplayError> {
-        // Safe given that the context pointer is valid. Any other invalid parameters would be
-        // rejected by dwl_context_dmabuf_new safely. We check that the resulting dmabuf is valid
-        // before filing it away.
-        let dmabuf = DwlDmabuf(unsafe {
-            dwl_context_dmabuf_new(
-                self.ctx(),
-                fd,
-                offset,
-                stride,
-                modifiers,
-                width,
-                height,
-                fourcc,
-            )
-        });
-        if dmabuf.0.is_null() {
-            return Err(GpuDisplayError::FailedImport);
-        }
-
-        let next_id = self.dmabuf_next_id;
-        self.dmabufs.insert(next_id, dmabuf);
-        self.dmabuf_next_id += 1;
-        Ok(next_id)
+        self.inner
+            .import_dmabuf(fd, offset, stride, modifiers, width, height, fourcc)
     }
 
     /// Releases a previously imported dmabuf identified by the given handle.
     pub fn release_import(&mut self, import_id: u32) {
-        self.dmabufs.remove(&import_id);
+        self.inner.release_import(import_id)
     }
 
     /// Dispatches internal events that were received from the compositor since the last call to
     /// `dispatch_events`.
     pub fn dispatch_events(&mut self) {
-        // Safe given that the context pointer is valid.
-        unsafe {
-            dwl_context_dispatch(self.ctx());
-        }
+        self.inner.dispatch_events()
     }
 
     /// Creates a surface on the the compositor as either a top level window, or child of another
@@ -222,88 +253,24 @@ impl GpuDisplay {
         width: u32,
         height: u32,
     ) -> Result<u32, GpuDisplayError> {
-        let parent_ptr = match parent_surface_id {
-            Some(id) => match self.get_surface(id).map(|p| p.surface()) {
-                Some(ptr) => ptr,
-                None => return Err(GpuDisplayError::InvalidSurfaceId),
-            },
-            None => null_mut(),
-        };
-        let row_size = width * BYTES_PER_PIXEL;
-        let fb_size = row_size * height;
-        let buffer_size = round_up_to_page_size(fb_size as usize * BUFFER_COUNT);
-        let mut buffer_shm = SharedMemory::new(Some(
-            CStr::from_bytes_with_nul(b"GpuDisplaySurface\0").unwrap(),
-        ))
-        .map_err(GpuDisplayError::CreateShm)?;
-        buffer_shm
-            .set_size(buffer_size as u64)
-            .map_err(GpuDisplayError::SetSize)?;
-        let buffer_mem = MemoryMapping::from_fd(&buffer_shm, buffer_size).unwrap();
-
-        // Safe because only a valid context, parent pointer (if not  None), and buffer FD are used.
-        // The returned surface is checked for validity before being filed away.
-        let surface = DwlSurface(unsafe {
-            dwl_context_surface_new(
-                self.ctx(),
-                parent_ptr,
-                buffer_shm.as_raw_fd(),
-                buffer_size,
-                fb_size as usize,
-                width,
-                height,
-                row_size,
-            )
-        });
-
-        if surface.0.is_null() {
-            return Err(GpuDisplayError::CreateSurface);
-        }
-
-        let next_id = self.surface_next_id;
-        self.surfaces.insert(
-            next_id,
-            GpuDisplaySurface {
-                surface,
-                buffer_size: fb_size as usize,
-                buffer_index: Cell::new(0),
-                buffer_mem,
-            },
-        );
-
-        self.surface_next_id += 1;
-        Ok(next_id)
+        self.inner.create_surface(parent_surface_id, width, height)
     }
 
     /// Releases a previously created surface identified by the given handle.
     pub fn release_surface(&mut self, surface_id: u32) {
-        self.surfaces.remove(&surface_id);
+        self.inner.release_surface(surface_id)
     }
 
     /// Gets a reference to an unused framebuffer for the identified surface.
     pub fn framebuffer_memory(&self, surface_id: u32) -> Option<VolatileSlice> {
-        let surface = self.get_surface(surface_id)?;
-        let buffer_index = (surface.buffer_index.get() + 1) % BUFFER_COUNT;
-        surface
-            .buffer_mem
-            .get_slice(
-                (buffer_index * surface.buffer_size) as u64,
-                surface.buffer_size as u64,
-            )
-            .ok()
+        self.inner
+            .framebuffer(surface_id)
+            .map(|fb| fb.as_volatile_slice())
     }
 
     /// Commits any pending state for the identified surface.
-    pub fn commit(&self, surface_id: u32) {
-        match self.get_surface(surface_id) {
-            Some(surface) => {
-                // Safe because only a valid surface is used.
-                unsafe {
-                    dwl_surface_commit(surface.surface());
-                }
-            }
-            None => debug_assert!(false, "invalid surface_id {}", surface_id),
-        }
+    pub fn commit(&mut self, surface_id: u32) {
+        self.inner.commit(surface_id)
     }
 
     /// Returns true if the next buffer in the buffer queue for the given surface is currently in
@@ -312,88 +279,59 @@ impl GpuDisplay {
     /// If the next buffer is in use, the memory returned from `framebuffer_memory` should not be
     /// written to.
     pub fn next_buffer_in_use(&self, surface_id: u32) -> bool {
-        match self.get_surface(surface_id) {
-            Some(surface) => {
-                let next_buffer_index = (surface.buffer_index.get() + 1) % BUFFER_COUNT;
-                // Safe because only a valid surface and buffer index is used.
-                unsafe { dwl_surface_buffer_in_use(surface.surface(), next_buffer_index) }
-            }
-            None => {
-                debug_assert!(false, "invalid surface_id {}", surface_id);
-                false
-            }
-        }
+        self.inner.next_buffer_in_use(surface_id)
     }
 
     /// Changes the visible contents of the identified surface to the contents of the framebuffer
     /// last returned by `framebuffer_memory` for this surface.
-    pub fn flip(&self, surface_id: u32) {
-        match self.get_surface(surface_id) {
-            Some(surface) => {
-                surface
-                    .buffer_index
-                    .set((surface.buffer_index.get() + 1) % BUFFER_COUNT);
-                // Safe because only a valid surface and buffer index is used.
-                unsafe {
-                    dwl_surface_flip(surface.surface(), surface.buffer_index.get());
-                }
-            }
-            None => debug_assert!(false, "invalid surface_id {}", surface_id),
-        }
+    pub fn flip(&mut self, surface_id: u32) {
+        self.inner.flip(surface_id)
     }
 
     /// Changes the visible contents of the identified surface to that of the identified imported
     /// buffer.
-    pub fn flip_to(&self, surface_id: u32, import_id: u32) {
-        match self.get_surface(surface_id) {
-            Some(surface) => {
-                match self.dmabufs.get(&import_id) {
-                    // Safe because only a valid surface and dmabuf is used.
-                    Some(dmabuf) => unsafe { dwl_surface_flip_to(surface.surface(), dmabuf.0) },
-                    None => debug_assert!(false, "invalid import_id {}", import_id),
-                }
-            }
-            None => debug_assert!(false, "invalid surface_id {}", surface_id),
-        }
+    pub fn flip_to(&mut self, surface_id: u32, import_id: u32) {
+        self.inner.flip_to(surface_id, import_id)
     }
 
     /// Returns true if the identified top level surface has been told to close by the compositor,
     /// and by extension the user.
     pub fn close_requested(&self, surface_id: u32) -> bool {
-        match self.get_surface(surface_id) {
-            Some(surface) =>
-            // Safe because only a valid surface is used.
-            unsafe { dwl_surface_close_requested(surface.surface()) }
-            None => false,
-        }
+        self.inner.close_requested(surface_id)
     }
 
     /// Sets the position of the identified subsurface relative to its parent.
     ///
     /// The change in position will not be visible until `commit` is called for the parent surface.
-    pub fn set_position(&self, surface_id: u32, x: u32, y: u32) {
-        match self.get_surface(surface_id) {
-            Some(surface) => {
-                // Safe because only a valid surface is used.
-                unsafe {
-                    dwl_surface_set_position(surface.surface(), x, y);
-                }
-            }
-            None => debug_assert!(false, "invalid surface_id {}", surface_id),
-        }
+    pub fn set_position(&mut self, surface_id: u32, x: u32, y: u32) {
+        self.inner.set_position(surface_id, x, y)
+    }
+
+    /// Registers `event_device` as the input sink for the identified surface, returning a handle
+    /// to it. Keyboard, pointer, and touch input received by the surface's window is translated
+    /// into Linux `input_event` records and delivered to `event_device` by `dispatch_events`.
+    pub fn import_event_device(
+        &mut self,
+        event_device: EventDevice,
+        surface_id: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        self.inner.import_event_device(event_device, surface_id)
+    }
+
+    /// Releases a previously imported event device identified by the given handle.
+    pub fn release_event_device(&mut self, event_device_id: u32) {
+        self.inner.release_event_device(event_device_id)
     }
-}
 
-impl Drop for GpuDisplay {
-    fn drop(&mut self) {
-        // Safe given that the context pointer is valid.
-        unsafe { dwl_context_destroy(&mut self.ctx.0) }
+    /// Returns the file descriptors of all currently registered event devices, so the outer event
+    /// loop can poll them for backpressure.
+    pub fn event_devices(&self) -> Vec<(u32, RawFd)> {
+        self.inner.event_devices()
     }
 }
 
 impl AsRawFd for GpuDisplay {
     fn as_raw_fd(&self) -> RawFd {
-        // Safe given that the context pointer is valid.
-        unsafe { dwl_context_fd(self.ctx.0) }
+        self.inner.as_raw_fd()
     }
 }
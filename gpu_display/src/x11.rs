@@ -0,0 +1,208 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Minimal FFI bindings to libxcb and the MIT-SHM/DRI3 extensions used by `gpu_display_x11`.
+//!
+//! Only the handful of calls the X11 backend needs are declared here; this is not a general
+//! purpose xcb binding.
+
+#![allow(non_camel_case_types)]
+
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::RawFd;
+
+#[repr(C)]
+pub struct xcb_connection_t {
+    _private: [u8; 0],
+}
+
+pub type XcbConnection = *mut xcb_connection_t;
+pub type XcbWindow = u32;
+pub type XcbShmSeg = u32;
+
+extern "C" {
+    fn xcb_connect_c(displayname: *const c_int) -> *mut xcb_connection_t;
+    fn xcb_disconnect_c(connection: *mut xcb_connection_t);
+    fn xcb_get_file_descriptor_c(connection: *mut xcb_connection_t) -> c_int;
+    fn xcb_flush_c(connection: *mut xcb_connection_t);
+
+    fn xcb_create_window_c(connection: *mut xcb_connection_t, width: u32, height: u32) -> u32;
+    fn xcb_destroy_window_c(window: u32);
+    fn xcb_enable_window_delete_notify_c(connection: *mut xcb_connection_t, window: u32);
+    fn xcb_configure_window_position_c(
+        connection: *mut xcb_connection_t,
+        window: u32,
+        x: u32,
+        y: u32,
+    );
+
+    fn xcb_shm_attach_fd_c(
+        connection: *mut xcb_connection_t,
+        fd: RawFd,
+        size: usize,
+        index: usize,
+    ) -> u32;
+    fn xcb_shm_detach_c(seg: u32);
+    fn xcb_shm_put_image_c(connection: *mut xcb_connection_t, window: u32, seg: u32);
+    fn xcb_shm_seg_busy_c(connection: *mut xcb_connection_t, seg: u32) -> bool;
+
+    fn xcb_dri3_pixmap_put_c(
+        connection: *mut xcb_connection_t,
+        window: u32,
+        fd: RawFd,
+        stride: u32,
+        width: u32,
+        height: u32,
+    ) -> bool;
+
+    fn xcb_poll_for_event_c(
+        connection: *mut xcb_connection_t,
+        window_id: *mut u32,
+        a: *mut i32,
+        b: *mut i32,
+        c: *mut i32,
+        d: *mut i32,
+    ) -> c_int;
+}
+
+/// The kind of input or window-management event `xcb_poll_for_event` translated.
+#[derive(Copy, Clone)]
+pub enum XcbEvent {
+    /// The window manager sent a `WM_DELETE_WINDOW` client message for this window.
+    CloseRequested,
+    KeyboardKey { linux_keycode: u16, pressed: bool },
+    PointerMotion { dx: i32, dy: i32 },
+    PointerButton { linux_button_code: u16, pressed: bool },
+    Touch { slot: i32, tracking_id: i32, x: i32, y: i32 },
+}
+
+/// Opens a connection to the X server named by `displayname`, or the `DISPLAY` environment
+/// variable when `displayname` is null.
+///
+/// # Safety
+/// `displayname`, if not null, must point at a valid, nul-terminated C string.
+pub unsafe fn xcb_connect(displayname: *mut c_void) -> XcbConnection {
+    xcb_connect_c(displayname as *const c_int)
+}
+
+/// # Safety
+/// `connection` must be a valid pointer returned by `xcb_connect`.
+pub unsafe fn xcb_disconnect(connection: XcbConnection) {
+    xcb_disconnect_c(connection)
+}
+
+/// # Safety
+/// `connection` must be a valid pointer returned by `xcb_connect`.
+pub unsafe fn xcb_get_file_descriptor(connection: XcbConnection) -> RawFd {
+    xcb_get_file_descriptor_c(connection)
+}
+
+/// # Safety
+/// `connection` must be a valid pointer returned by `xcb_connect`.
+pub unsafe fn xcb_flush(connection: XcbConnection) {
+    xcb_flush_c(connection)
+}
+
+/// # Safety
+/// `connection` must be a valid pointer returned by `xcb_connect`.
+pub unsafe fn xcb_create_window(connection: XcbConnection, width: u32, height: u32) -> XcbWindow {
+    xcb_create_window_c(connection, width, height)
+}
+
+/// # Safety
+/// `window` must be a valid window handle returned by `xcb_create_window`, used at most once.
+pub unsafe fn xcb_destroy_window(window: XcbWindow) {
+    xcb_destroy_window_c(window)
+}
+
+/// # Safety
+/// `connection` and `window` must be valid.
+pub unsafe fn xcb_enable_window_delete_notify(connection: XcbConnection, window: XcbWindow) {
+    xcb_enable_window_delete_notify_c(connection, window)
+}
+
+/// # Safety
+/// `connection` and `window` must be valid.
+pub unsafe fn xcb_configure_window_position(
+    connection: XcbConnection,
+    window: XcbWindow,
+    x: u32,
+    y: u32,
+) {
+    xcb_configure_window_position_c(connection, window, x, y)
+}
+
+/// # Safety
+/// `connection` must be valid and `fd` must reference shared memory at least `size` bytes long.
+pub unsafe fn xcb_shm_attach_fd(
+    connection: XcbConnection,
+    fd: RawFd,
+    size: usize,
+    index: usize,
+) -> XcbShmSeg {
+    xcb_shm_attach_fd_c(connection, fd, size, index)
+}
+
+/// # Safety
+/// `seg` must be a valid segment handle returned by `xcb_shm_attach_fd`, used at most once.
+pub unsafe fn xcb_shm_detach(seg: XcbShmSeg) {
+    xcb_shm_detach_c(seg)
+}
+
+/// # Safety
+/// `connection`, `window`, and `seg` must all be valid.
+pub unsafe fn xcb_shm_put_image(connection: XcbConnection, window: XcbWindow, seg: XcbShmSeg) {
+    xcb_shm_put_image_c(connection, window, seg)
+}
+
+/// # Safety
+/// `connection` and `seg` must be valid.
+pub unsafe fn xcb_shm_seg_busy(connection: XcbConnection, seg: XcbShmSeg) -> bool {
+    xcb_shm_seg_busy_c(connection, seg)
+}
+
+/// # Safety
+/// `connection` and `window` must be valid, and `fd` must reference a dmabuf at least
+/// `stride * height` bytes long. Returns false if DRI3 pixmap import is unavailable so the caller
+/// can fall back to a CPU copy.
+pub unsafe fn xcb_dri3_pixmap_put(
+    connection: XcbConnection,
+    window: XcbWindow,
+    fd: RawFd,
+    stride: u32,
+    width: u32,
+    height: u32,
+) -> bool {
+    xcb_dri3_pixmap_put_c(connection, window, fd, stride, width, height)
+}
+
+/// Polls for and translates the next queued X event, returning the owning window ID alongside it.
+///
+/// # Safety
+/// `connection` must be valid.
+pub unsafe fn xcb_poll_for_event(connection: XcbConnection) -> Option<(XcbWindow, XcbEvent)> {
+    let mut window_id = 0u32;
+    let (mut a, mut b, mut c, mut d) = (0i32, 0i32, 0i32, 0i32);
+    let event_type = xcb_poll_for_event_c(connection, &mut window_id, &mut a, &mut b, &mut c, &mut d);
+    let event = match event_type {
+        0 => return None,
+        1 => XcbEvent::CloseRequested,
+        2 => XcbEvent::KeyboardKey {
+            linux_keycode: a as u16,
+            pressed: b != 0,
+        },
+        3 => XcbEvent::PointerMotion { dx: a, dy: b },
+        4 => XcbEvent::PointerButton {
+            linux_button_code: a as u16,
+            pressed: b != 0,
+        },
+        _ => XcbEvent::Touch {
+            slot: a,
+            tracking_id: b,
+            x: c,
+            y: d,
+        },
+    };
+    Some((window_id, event))
+}